@@ -0,0 +1,85 @@
+use crate::elf::RomSegment;
+
+/// Compute the minimal set of sector-aligned erase regions covering every
+/// segment in `segments`, for `sector_size`-byte flash sectors.
+///
+/// Adjacent or overlapping segments are coalesced into a single erase run,
+/// so a sector shared by two segments (or a segment that doesn't start on a
+/// sector boundary) is only erased once. Run this once up front and issue
+/// whole-region erases before streaming segment writes, rather than erasing
+/// per block as each segment is written.
+pub fn erase_plan<'a>(
+    segments: impl IntoIterator<Item = &'a RomSegment<'a>>,
+    sector_size: u32,
+) -> Vec<(u32, u32)> {
+    let mut ranges: Vec<(u32, u32)> = segments
+        .into_iter()
+        .map(|segment| {
+            let start = segment.addr - (segment.addr % sector_size);
+            let end = segment.addr + segment.data.len() as u32;
+            let end = (end + sector_size - 1) / sector_size * sector_size;
+            (start, end)
+        })
+        .collect();
+
+    ranges.sort_unstable_by_key(|&(start, _)| start);
+
+    let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    merged
+        .into_iter()
+        .map(|(start, end)| (start, end - start))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    fn segment(addr: u32, len: usize) -> RomSegment<'static> {
+        RomSegment {
+            addr,
+            data: Cow::Owned(vec![0u8; len]),
+        }
+    }
+
+    #[test]
+    fn non_aligned_segment_erases_its_whole_sector() {
+        let segments = [segment(0x10, 4)];
+        let plan = erase_plan(&segments, 0x1000);
+
+        assert_eq!(plan, vec![(0x0, 0x1000)]);
+    }
+
+    #[test]
+    fn segments_sharing_a_sector_erase_once() {
+        // both segments fall inside the same 0x1000 sector
+        let segments = [segment(0x100, 0x10), segment(0x200, 0x10)];
+        let plan = erase_plan(&segments, 0x1000);
+
+        assert_eq!(plan, vec![(0x0, 0x1000)]);
+    }
+
+    #[test]
+    fn adjacent_sectors_coalesce_into_one_run() {
+        let segments = [segment(0x0, 0x1000), segment(0x1000, 0x1000)];
+        let plan = erase_plan(&segments, 0x1000);
+
+        assert_eq!(plan, vec![(0x0, 0x2000)]);
+    }
+
+    #[test]
+    fn disjoint_segments_stay_separate() {
+        let segments = [segment(0x0, 0x10), segment(0x5000, 0x10)];
+        let plan = erase_plan(&segments, 0x1000);
+
+        assert_eq!(plan, vec![(0x0, 0x1000), (0x5000, 0x1000)]);
+    }
+}
@@ -0,0 +1,53 @@
+use super::{build_image_segments, ChipType, FlashConfig, SpiRegisters};
+use crate::elf::{FirmwareImage, RomSegment};
+use crate::Error;
+
+const IROM_MAP_START: u32 = 0x4008_0000;
+const IROM_MAP_END: u32 = 0x4080_0000;
+
+/// Default flash offset for the app image in the single-app layout
+/// (`FlashConfig::default()`), matching ESP-IDF's default partition table
+const DEFAULT_IMAGE_ADDR: u32 = 0x10000;
+
+/// ESP32-S2 chip type, single-core Xtensa LX7
+pub struct Esp32s2;
+
+impl ChipType for Esp32s2 {
+    const CHIP_DETECT_MAGIC_VALUE: u32 = 0x000007c6;
+
+    const SPI_REGISTERS: SpiRegisters = SpiRegisters {
+        base: 0x3f40_2000,
+        usr_offset: 0x18,
+        usr1_offset: 0x1c,
+        usr2_offset: 0x20,
+        w0_offset: 0x58,
+        mosi_length_offset: Some(0x24),
+        miso_length_offset: Some(0x28),
+    };
+
+    const SUPPORTS_APPENDED_DIGEST: bool = true;
+
+    fn get_flash_segments<'a>(
+        image: &'a FirmwareImage,
+        append_digest: bool,
+        config: &FlashConfig,
+    ) -> Box<dyn Iterator<Item = Result<RomSegment<'a>, Error>> + 'a> {
+        let result = build_image_segments(
+            image,
+            DEFAULT_IMAGE_ADDR,
+            Self::SUPPORTS_APPENDED_DIGEST,
+            append_digest,
+            config,
+        )
+        .map(|segments| segments.into_iter().map(Ok));
+
+        match result {
+            Ok(segments) => Box::new(segments),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn addr_is_flash(addr: u32) -> bool {
+        (IROM_MAP_START..IROM_MAP_END).contains(&addr)
+    }
+}
@@ -0,0 +1,54 @@
+use super::{build_image_segments, ChipType, FlashConfig, SpiRegisters};
+use crate::elf::{FirmwareImage, RomSegment};
+use crate::Error;
+
+const IROM_MAP_START: u32 = 0x4020_0000;
+const IROM_MAP_END: u32 = 0x4030_0000;
+
+/// Default flash offset for the app image in the single-app layout
+/// (`FlashConfig::default()`)
+const DEFAULT_IMAGE_ADDR: u32 = 0x0;
+
+/// ESP8266 chip type
+pub struct Esp8266;
+
+impl ChipType for Esp8266 {
+    const CHIP_DETECT_MAGIC_VALUE: u32 = 0xfff0_c101;
+
+    const SPI_REGISTERS: SpiRegisters = SpiRegisters {
+        base: 0x6000_0200,
+        usr_offset: 0x1c,
+        usr1_offset: 0x20,
+        usr2_offset: 0x24,
+        w0_offset: 0x40,
+        mosi_length_offset: None,
+        miso_length_offset: None,
+    };
+
+    // ESP8266's ROM bootloader predates the appended-digest layout; leave
+    // SUPPORTS_APPENDED_DIGEST at its default of `false`.
+
+    fn get_flash_segments<'a>(
+        image: &'a FirmwareImage,
+        append_digest: bool,
+        config: &FlashConfig,
+    ) -> Box<dyn Iterator<Item = Result<RomSegment<'a>, Error>> + 'a> {
+        let result = build_image_segments(
+            image,
+            DEFAULT_IMAGE_ADDR,
+            Self::SUPPORTS_APPENDED_DIGEST,
+            append_digest,
+            config,
+        )
+        .map(|segments| segments.into_iter().map(Ok));
+
+        match result {
+            Ok(segments) => Box::new(segments),
+            Err(e) => Box::new(std::iter::once(Err(e))),
+        }
+    }
+
+    fn addr_is_flash(addr: u32) -> bool {
+        (IROM_MAP_START..IROM_MAP_END).contains(&addr)
+    }
+}
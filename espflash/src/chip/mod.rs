@@ -1,15 +1,51 @@
+use crate::connection::Connection;
 use crate::elf::{FirmwareImage, RomSegment};
 use crate::Error;
-use bytemuck::{Pod, Zeroable};
+use bytemuck::{bytes_of, Pod, Zeroable};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
 use std::str::FromStr;
 
+/// SPI flash "read data" command, per the standard JEDEC command set
+const SPI_FLASH_READ: u32 = 0x03;
+/// SPI flash "read JEDEC ID" command: returns manufacturer, memory type and
+/// capacity bytes, per the standard JEDEC command set
+const SPI_FLASH_RDID: u32 = 0x9f;
+/// SPI USR register bit that enables the command phase of a USR transaction
+const SPI_USR_COMMAND: u32 = 1 << 31;
+/// SPI USR register bit that enables the MOSI (address) phase of a USR transaction
+const SPI_USR_ADDR: u32 = 1 << 30;
+/// SPI USR register bit that enables the MISO (data-in) phase of a USR transaction
+const SPI_USR_MISO: u32 = 1 << 28;
+
+/// How many bytes of flash to read in a single USR transaction: `w0` is one
+/// 32-bit register wide, so at most 4 bytes come back per round trip.
+fn flash_read_chunk_len(remaining: u32) -> u32 {
+    remaining.min(4)
+}
+
+/// Encode a byte length as the (length-in-bits-minus-one) value the
+/// `mosi_length`/`miso_length` registers expect.
+fn spi_bit_length(byte_len: u32) -> u32 {
+    byte_len * 8 - 1
+}
+
 pub use esp32::Esp32;
+pub use esp32c3::Esp32c3;
 pub use esp32s2::Esp32s2;
+pub use esp32s3::Esp32s3;
 pub use esp8266::Esp8266;
 
+mod erase;
 mod esp32;
+mod esp32c3;
 mod esp32s2;
+mod esp32s3;
 mod esp8266;
+mod partition;
+
+pub use erase::erase_plan;
+pub use partition::{BootHeaderCfg, FlashConfig, PartitionEntry, PartitionTable};
 
 const ESP_MAGIC: u8 = 0xe9;
 const WP_PIN_DISABLED: u8 = 0xEE;
@@ -21,11 +57,30 @@ pub trait ChipType {
     const SPI_REGISTERS: SpiRegisters;
 
     /// Get the firmware segments for writing an image to flash
+    ///
+    /// When `append_digest` is set and the chip's `SUPPORTS_APPENDED_DIGEST`
+    /// is `true`, the image's `ExtendedHeader::append_digest` flag is set and
+    /// a SHA256 digest of the full image is appended after the checksum,
+    /// matching the layout the ROM bootloader verifies on boot. On other
+    /// chips `append_digest` is ignored.
+    ///
+    /// `config` supplies the bootloader/partition-table offsets and the
+    /// flash mode/frequency written into `EspCommonHeader`, plus any
+    /// user-defined partitions to merge in alongside the app image; pass
+    /// `&FlashConfig::default()` to get this chip's built-in single-app layout.
     fn get_flash_segments<'a>(
         image: &'a FirmwareImage,
+        append_digest: bool,
+        config: &FlashConfig,
     ) -> Box<dyn Iterator<Item = Result<RomSegment<'a>, Error>> + 'a>;
 
     fn addr_is_flash(addr: u32) -> bool;
+
+    /// Whether this chip's ROM bootloader understands `ExtendedHeader::append_digest`
+    ///
+    /// Only ESP32 and ESP32-S2 support the appended SHA256 digest; other chips
+    /// should leave this at the default of `false`.
+    const SUPPORTS_APPENDED_DIGEST: bool = false;
 }
 
 pub struct SpiRegisters {
@@ -66,6 +121,167 @@ impl SpiRegisters {
     pub fn miso_length(&self) -> Option<u32> {
         self.miso_length_offset.map(|offset| self.base + offset)
     }
+
+    /// Read `len` bytes of flash starting at `addr` by driving the SPI
+    /// peripheral directly through [`cmd`][Self::cmd], [`usr`][Self::usr] and
+    /// [`w0`][Self::w0].
+    pub fn read_flash(
+        &self,
+        connection: &mut Connection,
+        addr: u32,
+        len: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(len as usize);
+
+        while (data.len() as u32) < len {
+            let chunk_len = flash_read_chunk_len(len - data.len() as u32);
+
+            connection.write_register(
+                self.usr(),
+                SPI_USR_COMMAND | SPI_USR_ADDR | SPI_USR_MISO,
+            )?;
+            if let Some(miso_length) = self.miso_length() {
+                connection.write_register(miso_length, spi_bit_length(chunk_len))?;
+            }
+            connection.write_register(self.usr1(), addr + data.len() as u32)?;
+            // issue the opcode and trigger the transaction with a single
+            // write; writing cmd again afterwards would clobber it before
+            // the peripheral has latched it
+            connection.write_register(self.cmd(), SPI_FLASH_READ)?;
+
+            let word = connection.read_register(self.w0())?;
+            data.extend_from_slice(&word.to_le_bytes()[..chunk_len as usize]);
+        }
+
+        Ok(data)
+    }
+
+    /// Autodetect the size of the attached flash chip by issuing the
+    /// standard JEDEC `0x9F` read-ID command and decoding the returned
+    /// capacity byte.
+    pub fn detect_flash_size(&self, connection: &mut Connection) -> Result<FlashSize, Error> {
+        connection.write_register(self.usr(), SPI_USR_COMMAND | SPI_USR_MISO)?;
+        if let Some(miso_length) = self.miso_length() {
+            connection.write_register(miso_length, spi_bit_length(3))?;
+        }
+        // issue the opcode and trigger the transaction with a single write,
+        // as in read_flash above
+        connection.write_register(self.cmd(), SPI_FLASH_RDID)?;
+
+        let id = connection.read_register(self.w0())?;
+        let capacity_byte = (id >> 16) as u8 & 0xff;
+
+        FlashSize::from_capacity_byte(capacity_byte)
+    }
+}
+
+/// The size of the attached SPI flash chip, as decoded from a JEDEC ID
+/// capacity byte (`size = 1 << capacity_byte`).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FlashSize {
+    Flash1Mb,
+    Flash2Mb,
+    Flash4Mb,
+    Flash8Mb,
+    Flash16Mb,
+    Flash32Mb,
+    Flash64Mb,
+    Flash128Mb,
+}
+
+impl FlashSize {
+    fn from_capacity_byte(capacity_byte: u8) -> Result<Self, Error> {
+        match capacity_byte {
+            0x14 => Ok(FlashSize::Flash1Mb),
+            0x15 => Ok(FlashSize::Flash2Mb),
+            0x16 => Ok(FlashSize::Flash4Mb),
+            0x17 => Ok(FlashSize::Flash8Mb),
+            0x18 => Ok(FlashSize::Flash16Mb),
+            0x19 => Ok(FlashSize::Flash32Mb),
+            0x1a => Ok(FlashSize::Flash64Mb),
+            0x1b => Ok(FlashSize::Flash128Mb),
+            _ => Err(Error::UnsupportedFlashSize(capacity_byte)),
+        }
+    }
+
+    /// Size of the flash chip, in bytes
+    pub fn size(self) -> u32 {
+        match self {
+            FlashSize::Flash1Mb => 1 * 1024 * 1024,
+            FlashSize::Flash2Mb => 2 * 1024 * 1024,
+            FlashSize::Flash4Mb => 4 * 1024 * 1024,
+            FlashSize::Flash8Mb => 8 * 1024 * 1024,
+            FlashSize::Flash16Mb => 16 * 1024 * 1024,
+            FlashSize::Flash32Mb => 32 * 1024 * 1024,
+            FlashSize::Flash64Mb => 64 * 1024 * 1024,
+            FlashSize::Flash128Mb => 128 * 1024 * 1024,
+        }
+    }
+
+    /// Encode this size into the high nibble of `EspCommonHeader::flash_config`,
+    /// as the ROM bootloader expects it
+    pub fn flash_config_nibble(self) -> u8 {
+        let encoded = match self {
+            FlashSize::Flash1Mb => 0,
+            FlashSize::Flash2Mb => 1,
+            FlashSize::Flash4Mb => 2,
+            FlashSize::Flash8Mb => 3,
+            FlashSize::Flash16Mb => 4,
+            FlashSize::Flash32Mb => 5,
+            FlashSize::Flash64Mb => 6,
+            FlashSize::Flash128Mb => 7,
+        };
+
+        encoded << 4
+    }
+}
+
+/// Check that an image of `image_len` bytes fits in `flash_size`, and merge
+/// the detected size into the high nibble of `base_flash_config` (preserving
+/// its low nibble, the mode/frequency bits) to produce the
+/// `EspCommonHeader::flash_config` byte to write, so users no longer need
+/// to pass `--flash-size` by hand.
+pub fn validate_and_merge_flash_size(
+    flash_size: FlashSize,
+    image_len: usize,
+    base_flash_config: u8,
+) -> Result<u8, Error> {
+    if image_len as u32 > flash_size.size() {
+        return Err(Error::ImageTooLarge {
+            image_len: image_len as u32,
+            flash_size: flash_size.size(),
+        });
+    }
+
+    Ok((base_flash_config & 0x0f) | flash_size.flash_config_nibble())
+}
+
+/// Re-read each segment of a just-written image from flash and confirm the
+/// bytes on the device match what was sent.
+///
+/// Returns `Ok(())` if every segment verifies, or `Error::VerificationFailed`
+/// naming the start address of the first segment that does not match.
+pub fn verify_image<'a>(
+    chip: Chip,
+    connection: &mut Connection,
+    segments: impl Iterator<Item = Result<RomSegment<'a>, Error>>,
+) -> Result<(), Error> {
+    let spi_registers = chip.spi_registers();
+
+    for segment in segments {
+        let segment = segment?;
+        let on_device = spi_registers.read_flash(
+            connection,
+            segment.addr,
+            segment.data.len() as u32,
+        )?;
+
+        if on_device != segment.data.as_ref() {
+            return Err(Error::VerificationFailed(segment.addr));
+        }
+    }
+
+    Ok(())
 }
 
 #[derive(Copy, Clone, Zeroable, Pod)]
@@ -78,22 +294,140 @@ struct ExtendedHeader {
     chip_id: u16,
     min_rev: u8,
     padding: [u8; 8],
+    /// Set to `1` when a SHA256 digest of the whole image follows the
+    /// padded checksum byte; the ROM bootloader checks this when secure
+    /// boot or flash encryption is enabled.
     append_digest: u8,
 }
 
+/// Pad `data` with zeros so its length (counting from the end of the
+/// `EspCommonHeader`) is one short of a multiple of 16, then push a
+/// trailing XOR checksum as the final byte to land exactly on that 16-byte
+/// boundary; if `append_digest` is set, append a SHA256 digest of
+/// everything emitted so far (including the checksum byte).
+///
+/// `data` must already contain the `EspCommonHeader`, `ExtendedHeader` and
+/// every `SegmentHeader`/segment pair for the image.
+pub(crate) fn checksum_and_digest(data: &mut Vec<u8>, append_digest: bool) {
+    let checksum = data[8..]
+        .iter()
+        .fold(0xefu8, |checksum, byte| checksum ^ byte);
+
+    while (data.len() - 8) % 16 != 15 {
+        data.push(0);
+    }
+    data.push(checksum);
+
+    if append_digest {
+        let digest = Sha256::digest(&data[..]);
+        data.extend_from_slice(&digest);
+    }
+}
+
+/// Build the single combined header+segments flash image (checksummed and,
+/// when requested, digested) that chips sharing this common header format
+/// emit, merged with any user-defined partitions from `config`.
+///
+/// `default_image_addr` is where the combined segment is placed in flash
+/// unless overridden by `config.boot_header.bootloader_offset` — it has
+/// nothing to do with the firmware's execution entry point, which is read
+/// off `image` itself and written into `EspCommonHeader::entry`.
+/// `partition_table_offset` places a generated partition-table descriptor
+/// segment alongside the image when `config.partition_table` has any
+/// partitions.
+pub(crate) fn build_image_segments<'a>(
+    image: &'a FirmwareImage,
+    default_image_addr: u32,
+    supports_appended_digest: bool,
+    append_digest: bool,
+    config: &FlashConfig,
+) -> Result<Vec<RomSegment<'static>>, Error> {
+    let segments: Vec<_> = image.segments().collect();
+    let image_addr = config
+        .boot_header
+        .bootloader_offset
+        .unwrap_or(default_image_addr);
+
+    let mut data = Vec::new();
+    data.extend_from_slice(bytes_of(&EspCommonHeader {
+        magic: ESP_MAGIC,
+        segment_count: segments.len() as u8,
+        flash_mode: config.boot_header.flash_mode,
+        flash_config: config.boot_header.flash_config,
+        entry: image.entry(),
+    }));
+    data.extend_from_slice(bytes_of(&ExtendedHeader {
+        wp_pin: WP_PIN_DISABLED,
+        clk_q_drv: 0,
+        d_cs_drv: 0,
+        gd_wp_drv: 0,
+        chip_id: 0,
+        min_rev: 0,
+        padding: [0; 8],
+        append_digest: (supports_appended_digest && append_digest) as u8,
+    }));
+
+    for segment in &segments {
+        data.extend_from_slice(bytes_of(&SegmentHeader {
+            addr: segment.addr,
+            length: segment.data.len() as u32,
+        }));
+        data.extend_from_slice(&segment.data);
+    }
+
+    checksum_and_digest(&mut data, supports_appended_digest && append_digest);
+
+    let mut rom_segments = vec![RomSegment {
+        addr: image_addr,
+        data: Cow::Owned(data),
+    }];
+
+    if !config.partition_table.partitions.is_empty() {
+        let partition_table_addr = config
+            .boot_header
+            .partition_table_offset
+            .unwrap_or(image_addr);
+        rom_segments.push(
+            config
+                .partition_table
+                .to_descriptor_segment(partition_table_addr)?,
+        );
+    }
+
+    rom_segments.extend(config.partition_table.to_segments()?);
+
+    Ok(rom_segments)
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Chip {
     Esp8266,
     Esp32,
     Esp32s2,
+    Esp32s3,
+    Esp32c3,
 }
 
 impl Chip {
+    /// Identify a chip from the magic value it reports; some chips (the
+    /// ESP32-S3 and ESP32-C3 among them) report a second magic value
+    /// depending on revision, so both `CHIP_DETECT_MAGIC_VALUE` and
+    /// `CHIP_DETECT_MAGIC_VALUE2` are checked.
     pub fn from_magic(magic: u32) -> Option<Self> {
         match magic {
-            Esp8266::CHIP_DETECT_MAGIC_VALUE => Some(Chip::Esp8266),
-            Esp32::CHIP_DETECT_MAGIC_VALUE => Some(Chip::Esp32),
-            Esp32s2::CHIP_DETECT_MAGIC_VALUE => Some(Chip::Esp32s2),
+            magic if magic == Esp8266::CHIP_DETECT_MAGIC_VALUE => Some(Chip::Esp8266),
+            magic if magic == Esp32::CHIP_DETECT_MAGIC_VALUE => Some(Chip::Esp32),
+            magic if magic == Esp32s2::CHIP_DETECT_MAGIC_VALUE => Some(Chip::Esp32s2),
+            magic if magic == Esp32s3::CHIP_DETECT_MAGIC_VALUE
+                || magic == Esp32s3::CHIP_DETECT_MAGIC_VALUE2 =>
+            {
+                Some(Chip::Esp32s3)
+            }
+            magic if magic == Esp32c3::CHIP_DETECT_MAGIC_VALUE
+                || magic == Esp32c3::CHIP_DETECT_MAGIC_VALUE2 =>
+            {
+                Some(Chip::Esp32c3)
+            }
             _ => None,
         }
     }
@@ -101,11 +435,15 @@ impl Chip {
     pub fn get_flash_segments<'a>(
         &self,
         image: &'a FirmwareImage,
+        append_digest: bool,
+        config: &FlashConfig,
     ) -> Box<dyn Iterator<Item = Result<RomSegment<'a>, Error>> + 'a> {
         match self {
-            Chip::Esp8266 => Esp8266::get_flash_segments(image),
-            Chip::Esp32 => Esp32::get_flash_segments(image),
-            Chip::Esp32s2 => Esp32s2::get_flash_segments(image),
+            Chip::Esp8266 => Esp8266::get_flash_segments(image, append_digest, config),
+            Chip::Esp32 => Esp32::get_flash_segments(image, append_digest, config),
+            Chip::Esp32s2 => Esp32s2::get_flash_segments(image, append_digest, config),
+            Chip::Esp32s3 => Esp32s3::get_flash_segments(image, append_digest, config),
+            Chip::Esp32c3 => Esp32c3::get_flash_segments(image, append_digest, config),
         }
     }
 
@@ -114,6 +452,8 @@ impl Chip {
             Chip::Esp8266 => Esp8266::addr_is_flash(addr),
             Chip::Esp32 => Esp32::addr_is_flash(addr),
             Chip::Esp32s2 => Esp32s2::addr_is_flash(addr),
+            Chip::Esp32s3 => Esp32s3::addr_is_flash(addr),
+            Chip::Esp32c3 => Esp32c3::addr_is_flash(addr),
         }
     }
 
@@ -122,6 +462,8 @@ impl Chip {
             Chip::Esp8266 => Esp8266::SPI_REGISTERS,
             Chip::Esp32 => Esp32::SPI_REGISTERS,
             Chip::Esp32s2 => Esp32s2::SPI_REGISTERS,
+            Chip::Esp32s3 => Esp32s3::SPI_REGISTERS,
+            Chip::Esp32c3 => Esp32c3::SPI_REGISTERS,
         }
     }
 }
@@ -133,6 +475,8 @@ impl FromStr for Chip {
         match s {
             "esp32" => Ok(Chip::Esp32),
             "esp32s2" => Ok(Chip::Esp32s2),
+            "esp32s3" => Ok(Chip::Esp32s3),
+            "esp32c3" => Ok(Chip::Esp32c3),
             "esp8266" => Ok(Chip::Esp8266),
             _ => Err(Error::UnrecognizedChip),
         }
@@ -155,3 +499,55 @@ struct SegmentHeader {
     addr: u32,
     length: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flash_read_chunk_len_caps_at_one_register_width() {
+        assert_eq!(flash_read_chunk_len(1), 1);
+        assert_eq!(flash_read_chunk_len(4), 4);
+        assert_eq!(flash_read_chunk_len(9), 4);
+    }
+
+    #[test]
+    fn spi_bit_length_is_byte_len_times_eight_minus_one() {
+        assert_eq!(spi_bit_length(1), 7);
+        assert_eq!(spi_bit_length(3), 23);
+        assert_eq!(spi_bit_length(4), 31);
+    }
+
+    #[test]
+    fn from_capacity_byte_decodes_known_sizes() {
+        assert_eq!(
+            FlashSize::from_capacity_byte(0x14).unwrap(),
+            FlashSize::Flash1Mb
+        );
+        assert_eq!(
+            FlashSize::from_capacity_byte(0x1b).unwrap(),
+            FlashSize::Flash128Mb
+        );
+    }
+
+    #[test]
+    fn from_capacity_byte_rejects_unknown_values() {
+        assert!(FlashSize::from_capacity_byte(0x00).is_err());
+    }
+
+    #[test]
+    fn checksum_is_last_byte_of_padded_block() {
+        let mut data = vec![0u8; 8]; // stand-in for an EspCommonHeader
+        data.extend_from_slice(&[0xaa, 0xbb, 0xcc]);
+
+        checksum_and_digest(&mut data, false);
+
+        assert_eq!((data.len() - 8) % 16, 0);
+
+        let expected_checksum = [0xaa, 0xbb, 0xcc].iter().fold(0xefu8, |c, b| c ^ b);
+        assert_eq!(*data.last().unwrap(), expected_checksum);
+        // the checksum must be the final byte, with zero padding before it,
+        // not zero padding after it
+        assert_eq!(data[data.len() - 2], 0);
+    }
+}
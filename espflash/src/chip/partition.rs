@@ -0,0 +1,136 @@
+use crate::elf::RomSegment;
+use crate::Error;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::fs;
+use std::path::Path;
+
+/// A single user-defined region of flash, e.g. an OTA slot, NVS or a SPIFFS
+/// partition, read verbatim from `image_path` and written at `offset`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PartitionEntry {
+    pub name: String,
+    pub offset: u32,
+    pub size: u32,
+    pub image_path: Option<String>,
+}
+
+/// A partition layout, parsed from a user-supplied TOML file.
+///
+/// An empty table (the `Default`) means "use this chip's built-in
+/// single-app layout", same as passing no `--partition-table` today.
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct PartitionTable {
+    #[serde(default, rename = "partition")]
+    pub partitions: Vec<PartitionEntry>,
+}
+
+impl PartitionTable {
+    /// Parse a partition table from TOML source
+    pub fn from_toml(text: &str) -> Result<Self, Error> {
+        toml::from_str(text).map_err(Error::InvalidPartitionTable)
+    }
+
+    /// Parse a partition table from a TOML file on disk
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let text = fs::read_to_string(path).map_err(Error::Io)?;
+        Self::from_toml(&text)
+    }
+
+    /// Render each configured partition as a `RomSegment`, for merging
+    /// alongside the app image's own segments.
+    pub fn to_segments(&self) -> Result<Vec<RomSegment<'static>>, Error> {
+        self.partitions
+            .iter()
+            .filter_map(|partition| partition.image_path.as_ref().map(|path| (partition, path)))
+            .map(|(partition, path)| {
+                let data = fs::read(path).map_err(Error::Io)?;
+                if data.len() as u32 > partition.size {
+                    return Err(Error::PartitionTooSmall(partition.name.clone()));
+                }
+                Ok(RomSegment {
+                    addr: partition.offset,
+                    data: Cow::Owned(data),
+                })
+            })
+            .collect()
+    }
+
+    /// Render the partition table itself (the directory of name/offset/size
+    /// entries, as opposed to the partition contents from `to_segments`) as a
+    /// `RomSegment` to be written at `addr`, i.e. `BootHeaderCfg::partition_table_offset`.
+    ///
+    /// This is a custom, espflash-only encoding (24-byte name + little-endian
+    /// offset + little-endian size per entry) and is *not* binary-compatible
+    /// with the ESP-IDF partition table format (which has a 2-byte magic,
+    /// type/subtype/flags bytes, and an MD5 checksum entry). Don't flash a
+    /// table produced here and expect an ESP-IDF bootloader built against the
+    /// real format to read it.
+    pub fn to_descriptor_segment(&self, addr: u32) -> Result<RomSegment<'static>, Error> {
+        let mut data = Vec::with_capacity(self.partitions.len() * 32);
+        for partition in &self.partitions {
+            let mut name = [0u8; 24];
+            let name_bytes = partition.name.as_bytes();
+            if name_bytes.len() > name.len() {
+                return Err(Error::PartitionNameTooLong(partition.name.clone()));
+            }
+            name[..name_bytes.len()].copy_from_slice(name_bytes);
+
+            data.extend_from_slice(&name);
+            data.extend_from_slice(&partition.offset.to_le_bytes());
+            data.extend_from_slice(&partition.size.to_le_bytes());
+        }
+
+        Ok(RomSegment {
+            addr,
+            data: Cow::Owned(data),
+        })
+    }
+}
+
+/// Offsets and flash parameters that control where the bootloader and
+/// partition table land, and how `EspCommonHeader::flash_mode` /
+/// `flash_config` are written.
+///
+/// Defaults match the layout `get_flash_segments` has always produced: a
+/// single app image with no separate bootloader/partition table segments.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BootHeaderCfg {
+    pub bootloader_offset: Option<u32>,
+    pub partition_table_offset: Option<u32>,
+    pub flash_mode: u8,
+    pub flash_config: u8,
+}
+
+impl Default for BootHeaderCfg {
+    fn default() -> Self {
+        BootHeaderCfg {
+            bootloader_offset: None,
+            partition_table_offset: None,
+            flash_mode: 0,
+            flash_config: 0,
+        }
+    }
+}
+
+/// Everything needed to lay a custom flash image out: the boot header
+/// parameters plus any user-defined partitions to merge in.
+///
+/// `FlashConfig::default()` reproduces the chip's built-in single-app
+/// layout that `get_flash_segments` always used before this existed.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FlashConfig {
+    pub boot_header: BootHeaderCfg,
+    pub partition_table: PartitionTable,
+}
+
+impl FlashConfig {
+    /// Load a `FlashConfig` from a TOML file, using chip defaults for the
+    /// `[boot_header]` table if the file only specifies partitions.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let text = fs::read_to_string(path).map_err(Error::Io)?;
+        toml::from_str(&text).map_err(Error::InvalidPartitionTable)
+    }
+}